@@ -0,0 +1,200 @@
+//! Union-find clustering of coreference mentions into CoNLL-2012 style
+//! entity IDs, plus the bracket-column rendering for that format.
+//!
+//! A mention is identified by `(sentence_index, first_token_id,
+//! last_token_id)`, where the token ids are the per-sentence positions
+//! already assigned by [`crate::negra_ids`]. Linking two mentions via
+//! [`Clusters::union`] merges their equivalence classes; [`Clusters::finish`]
+//! assigns each surviving class a stable, small integer id in first-seen
+//! order.
+
+use std::collections::HashMap;
+
+/// A mention span: the sentence it occurs in and the first/last token id
+/// (inclusive) it covers.
+pub type Span = (usize, usize, usize);
+
+/// Union-find over mention spans, used to group spans linked by
+/// `R=coreferential` (or other relation) annotations into entities.
+pub struct Clusters {
+    spans: Vec<Span>,
+    index: HashMap<Span, usize>,
+    parent: Vec<usize>,
+}
+
+impl Clusters {
+    pub fn new() -> Self {
+        Clusters {
+            spans: Vec::new(),
+            index: HashMap::new(),
+            parent: Vec::new(),
+        }
+    }
+
+    fn node(&mut self, span: Span) -> usize {
+        if let Some(&idx) = self.index.get(&span) {
+            return idx;
+        }
+        let idx = self.parent.len();
+        self.parent.push(idx);
+        self.spans.push(span);
+        self.index.insert(span, idx);
+        idx
+    }
+
+    fn find(&mut self, node: usize) -> usize {
+        if self.parent[node] != node {
+            self.parent[node] = self.find(self.parent[node]);
+        }
+        self.parent[node]
+    }
+
+    /// Unions the equivalence classes of two mention spans, registering
+    /// either span as a new mention if it hasn't been seen yet.
+    pub fn union(&mut self, a: Span, b: Span) {
+        let a = self.node(a);
+        let b = self.node(b);
+        let a_root = self.find(a);
+        let b_root = self.find(b);
+        if a_root != b_root {
+            self.parent[a_root] = b_root;
+        }
+    }
+
+    /// Assigns every registered mention span a stable, small integer
+    /// cluster id, in first-seen order of its equivalence class.
+    pub fn finish(mut self) -> HashMap<Span, usize> {
+        let mut root_to_cluster = HashMap::new();
+        let mut assignment = HashMap::with_capacity(self.spans.len());
+        for idx in 0..self.spans.len() {
+            let root = self.find(idx);
+            let next_id = root_to_cluster.len();
+            let cluster = *root_to_cluster.entry(root).or_insert(next_id);
+            assignment.insert(self.spans[idx], cluster);
+        }
+        assignment
+    }
+}
+
+impl Default for Clusters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a set of token ids into the maximal contiguous runs it contains,
+/// returning each run as a `(first, last)` token id pair. NEGRA allows
+/// discontinuous constituents, but the CoNLL-2012 bracket format assumes
+/// every mention is a contiguous span; this lets callers treat a
+/// discontinuous mention as several contiguous sub-mentions.
+pub fn contiguous_spans(token_ids: &[usize]) -> Vec<(usize, usize)> {
+    let mut sorted = token_ids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut spans = Vec::new();
+    let mut iter = sorted.into_iter();
+    if let Some(first) = iter.next() {
+        let mut start = first;
+        let mut end = first;
+        for id in iter {
+            if id == end + 1 {
+                end = id;
+            } else {
+                spans.push((start, end));
+                start = id;
+                end = id;
+            }
+        }
+        spans.push((start, end));
+    }
+    spans
+}
+
+/// Renders the per-token CoNLL-2012 bracket markers for one sentence.
+///
+/// `mentions` holds `(first_token_id, last_token_id, cluster_id)` triples
+/// for every mention in the sentence. Returns a map from token id to the
+/// marker string for that token (tokens with no marker are absent).
+pub fn bracket_markers(mentions: &[(usize, usize, usize)]) -> HashMap<usize, String> {
+    let mut opens: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    let mut closes: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    let mut singletons: HashMap<usize, Vec<usize>> = HashMap::new();
+
+    for &(first, last, cluster) in mentions {
+        if first == last {
+            singletons.entry(first).or_default().push(cluster);
+        } else {
+            opens.entry(first).or_default().push((last - first, cluster));
+            closes.entry(last).or_default().push((last - first, cluster));
+        }
+    }
+
+    let mut markers: HashMap<usize, Vec<String>> = HashMap::new();
+
+    for (&token, clusters) in &opens {
+        // Longest span first, so nesting brackets stay well-formed.
+        let mut clusters = clusters.clone();
+        clusters.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, cluster) in clusters {
+            markers.entry(token).or_default().push(format!("({}", cluster));
+        }
+    }
+    for (&token, clusters) in &closes {
+        // Shortest span first, closing the most recently opened bracket.
+        let mut clusters = clusters.clone();
+        clusters.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, cluster) in clusters {
+            markers.entry(token).or_default().push(format!("{})", cluster));
+        }
+    }
+    for (&token, clusters) in &singletons {
+        for cluster in clusters {
+            markers.entry(token).or_default().push(format!("({})", cluster));
+        }
+    }
+
+    markers
+        .into_iter()
+        .map(|(token, parts)| (token, parts.join("|")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unions_linked_spans_into_one_cluster() {
+        let mut clusters = Clusters::new();
+        clusters.union((0, 0, 1), (1, 3, 3));
+        clusters.union((1, 3, 3), (2, 5, 6));
+        let assignment = clusters.finish();
+        assert_eq!(assignment[&(0, 0, 1)], assignment[&(1, 3, 3)]);
+        assert_eq!(assignment[&(1, 3, 3)], assignment[&(2, 5, 6)]);
+    }
+
+    #[test]
+    fn unlinked_spans_get_distinct_clusters() {
+        let mut clusters = Clusters::new();
+        clusters.union((0, 0, 0), (0, 1, 1));
+        clusters.union((0, 2, 2), (0, 3, 3));
+        let assignment = clusters.finish();
+        assert_ne!(assignment[&(0, 0, 0)], assignment[&(0, 2, 2)]);
+    }
+
+    #[test]
+    fn splits_discontinuous_token_ids_into_contiguous_runs() {
+        assert_eq!(contiguous_spans(&[1, 2, 3, 7, 8]), vec![(1, 3), (7, 8)]);
+        assert_eq!(contiguous_spans(&[5]), vec![(5, 5)]);
+    }
+
+    #[test]
+    fn renders_nested_and_singleton_brackets() {
+        let markers = bracket_markers(&[(0, 2, 0), (1, 1, 1), (3, 3, 2)]);
+        assert_eq!(markers[&0], "(0");
+        assert_eq!(markers[&1], "(1)");
+        assert_eq!(markers[&2], "0)");
+        assert_eq!(markers[&3], "(2)");
+    }
+}
@@ -0,0 +1,317 @@
+//! Small expression language for selecting coreference links.
+//!
+//! A [`Query`] is evaluated once per candidate link while walking the
+//! `nonterminals()` of a sentence, and decides whether that link's `coref`
+//! feature should be written out. Predicates look at the distance between
+//! the referring mention and its antecedent, the number of terminals the
+//! mention spans, and the relation type recorded in the NEGRA comment.
+//! Predicates combine with `and`, `or`, `not`, and parentheses, e.g.
+//! `distance >1 and not rel=coreferential`.
+
+use std::fmt;
+
+/// Facts about a single coreference candidate that a [`Query`] is
+/// evaluated against.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    /// Sentence index of the referring mention minus the sentence index
+    /// of its resolved antecedent.
+    pub distance: i64,
+    /// Number of terminals dominated by the referring mention.
+    pub len: usize,
+    /// Relation type token from the NEGRA comment, e.g. `"coreferential"`.
+    pub rel: &'a str,
+}
+
+/// A parsed query expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    DistanceLt(i64),
+    DistanceGt(i64),
+    LenGt(usize),
+    Rel(String),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    /// Evaluates the query against a single candidate link.
+    pub fn eval(&self, candidate: &Candidate) -> bool {
+        match self {
+            Query::DistanceLt(n) => candidate.distance < *n,
+            Query::DistanceGt(n) => candidate.distance > *n,
+            Query::LenGt(n) => candidate.len > *n,
+            Query::Rel(rel) => candidate.rel == rel,
+            Query::And(lhs, rhs) => lhs.eval(candidate) && rhs.eval(candidate),
+            Query::Or(lhs, rhs) => lhs.eval(candidate) || rhs.eval(candidate),
+            Query::Not(inner) => !inner.eval(candidate),
+        }
+    }
+}
+
+/// Error produced while parsing a query expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid query: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(String),
+    Lt,
+    Gt,
+    Eq,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '<' => {
+                chars.next();
+                tokens.push(Token::Lt);
+            }
+            '>' => {
+                chars.next();
+                tokens.push(Token::Gt);
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Eq);
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                number.push(c);
+                chars.next();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                // `-` is allowed after the first character so relation
+                // names like `split-antecedent` tokenize as one ident.
+                let mut ident = String::new();
+                ident.push(c);
+                chars.next();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '-' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c => return Err(ParseError(format!("unexpected character '{}'", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect_ident(&mut self, expected: &str) -> Result<(), ParseError> {
+        match self.advance() {
+            Some(Token::Ident(ref ident)) if ident == expected => Ok(()),
+            other => Err(ParseError(format!(
+                "expected '{}', got {:?}",
+                expected, other
+            ))),
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<Query, ParseError> {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> Result<Query, ParseError> {
+        let mut lhs = self.parse_and()?;
+        while let Some(Token::Ident(ident)) = self.peek() {
+            if ident == "or" {
+                self.advance();
+                let rhs = self.parse_and()?;
+                lhs = Query::Or(Box::new(lhs), Box::new(rhs));
+            } else {
+                break;
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, ParseError> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(Token::Ident(ident)) = self.peek() {
+            if ident == "and" {
+                self.advance();
+                let rhs = self.parse_unary()?;
+                lhs = Query::And(Box::new(lhs), Box::new(rhs));
+            } else {
+                break;
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, ParseError> {
+        if let Some(Token::Ident(ident)) = self.peek() {
+            if ident == "not" {
+                self.advance();
+                let inner = self.parse_unary()?;
+                return Ok(Query::Not(Box::new(inner)));
+            }
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, ParseError> {
+        match self.advance() {
+            Some(Token::LParen) => {
+                let inner = self.parse_query()?;
+                match self.advance() {
+                    Some(Token::RParen) => Ok(inner),
+                    other => Err(ParseError(format!("expected ')', got {:?}", other))),
+                }
+            }
+            Some(Token::Ident(ident)) if ident == "distance" => self.parse_distance(),
+            Some(Token::Ident(ident)) if ident == "len" => self.parse_len(),
+            Some(Token::Ident(ident)) if ident == "rel" => self.parse_rel(),
+            other => Err(ParseError(format!("expected predicate, got {:?}", other))),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<i64, ParseError> {
+        match self.advance() {
+            Some(Token::Number(number)) => number
+                .parse()
+                .map_err(|_| ParseError(format!("invalid number '{}'", number))),
+            other => Err(ParseError(format!("expected number, got {:?}", other))),
+        }
+    }
+
+    fn parse_distance(&mut self) -> Result<Query, ParseError> {
+        match self.advance() {
+            Some(Token::Lt) => Ok(Query::DistanceLt(self.parse_number()?)),
+            Some(Token::Gt) => Ok(Query::DistanceGt(self.parse_number()?)),
+            other => Err(ParseError(format!("expected '<' or '>', got {:?}", other))),
+        }
+    }
+
+    fn parse_len(&mut self) -> Result<Query, ParseError> {
+        match self.advance() {
+            Some(Token::Gt) => Ok(Query::LenGt(self.parse_number()? as usize)),
+            other => Err(ParseError(format!("expected '>', got {:?}", other))),
+        }
+    }
+
+    fn parse_rel(&mut self) -> Result<Query, ParseError> {
+        match self.advance() {
+            Some(Token::Eq) => match self.advance() {
+                Some(Token::Ident(ident)) => Ok(Query::Rel(ident)),
+                other => Err(ParseError(format!("expected relation name, got {:?}", other))),
+            },
+            other => Err(ParseError(format!("expected '=', got {:?}", other))),
+        }
+    }
+}
+
+/// Parses a query expression, e.g. `distance >1 and not rel=coreferential`.
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let query = parser.parse_query()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(ParseError(format!(
+            "unexpected trailing tokens starting at {:?}",
+            parser.tokens[parser.pos]
+        )));
+    }
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(distance: i64, len: usize, rel: &str) -> Candidate {
+        Candidate { distance, len, rel }
+    }
+
+    #[test]
+    fn parses_and_evaluates_simple_predicate() {
+        let query = parse("distance >1").unwrap();
+        assert!(query.eval(&candidate(2, 1, "coreferential")));
+        assert!(!query.eval(&candidate(1, 1, "coreferential")));
+    }
+
+    #[test]
+    fn parses_and_evaluates_conjunction() {
+        let query = parse("distance >1 and len >3").unwrap();
+        assert!(query.eval(&candidate(2, 4, "coreferential")));
+        assert!(!query.eval(&candidate(2, 3, "coreferential")));
+    }
+
+    #[test]
+    fn parses_and_evaluates_negated_disjunction_with_parens() {
+        let query = parse("not (distance <0 or rel=anaphoric)").unwrap();
+        assert!(query.eval(&candidate(1, 1, "coreferential")));
+        assert!(!query.eval(&candidate(-1, 1, "coreferential")));
+        assert!(!query.eval(&candidate(1, 1, "anaphoric")));
+    }
+
+    #[test]
+    fn parses_hyphenated_relation_name() {
+        let query = parse("rel=split-antecedent").unwrap();
+        assert!(query.eval(&candidate(1, 1, "split-antecedent")));
+        assert!(!query.eval(&candidate(1, 1, "coreferential")));
+    }
+
+    #[test]
+    fn rejects_malformed_query() {
+        assert!(parse("distance >").is_err());
+        assert!(parse("distance >1 and").is_err());
+        assert!(parse("(distance >1").is_err());
+    }
+}
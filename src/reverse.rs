@@ -0,0 +1,133 @@
+//! Parsing support for the reverse `conll -> negra` pipeline.
+//!
+//! The forward conversion writes a `coref` feature shaped like
+//! `[(sent,[id,id,...]),(sent,[id,...])]`, where each `(sent,[ids])` entry
+//! names one antecedent mention: the sentence it occurs in and the token
+//! ids it spans. [`parse_coref_feature`] turns that string back into the
+//! list of `(sentence_id, token_ids)` entries it was built from.
+
+/// One antecedent entry parsed out of a `coref` feature: the antecedent's
+/// (0-based) sentence index and the token ids it spans.
+pub type CorefEntry = (usize, Vec<usize>);
+
+/// Parses a `coref` feature value of the form
+/// `[(sent,[id,id,...]),(sent,[id,...])]` back into its entries.
+pub fn parse_coref_feature(feature: &str) -> Vec<CorefEntry> {
+    let inner = feature
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(feature);
+    if inner.is_empty() {
+        return Vec::new();
+    }
+
+    let raw_entries: Vec<&str> = inner.split("),(").collect();
+    let last = raw_entries.len() - 1;
+    raw_entries
+        .into_iter()
+        .enumerate()
+        .map(|(idx, raw)| {
+            let mut entry = raw.to_string();
+            if idx != 0 {
+                entry = format!("({}", entry);
+            }
+            if idx != last {
+                entry = format!("{})", entry);
+            }
+            parse_entry(&entry)
+        })
+        .collect()
+}
+
+/// Parses a `reltype` feature value of the form `[rel,rel,...]` back into
+/// its list of relation type names, one per entry [`parse_coref_feature`]
+/// returns for the `coref` feature on the same terminal.
+pub fn parse_reltype_feature(feature: &str) -> Vec<String> {
+    let inner = feature
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(feature);
+    if inner.is_empty() {
+        return Vec::new();
+    }
+    inner.split(',').map(|s| s.to_string()).collect()
+}
+
+fn parse_entry(entry: &str) -> CorefEntry {
+    let entry = entry
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .unwrap_or(entry);
+    let (sent_id, ids) = entry.split_once(',').expect("malformed coref entry");
+    let sent_id = sent_id.parse::<usize>().expect("invalid sentence id");
+    let ids = ids
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(ids);
+    let ids = if ids.is_empty() {
+        Vec::new()
+    } else {
+        ids.split(',')
+            .map(|id| id.parse::<usize>().expect("invalid token id"))
+            .collect()
+    };
+    (sent_id, ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_entry() {
+        assert_eq!(
+            parse_coref_feature("[(1,[2,3])]"),
+            vec![(1, vec![2, 3])]
+        );
+    }
+
+    #[test]
+    fn parses_concatenated_entries() {
+        assert_eq!(
+            parse_coref_feature("[(1,[2,3]),(2,[4])]"),
+            vec![(1, vec![2, 3]), (2, vec![4])]
+        );
+    }
+
+    #[test]
+    fn parses_single_reltype() {
+        assert_eq!(
+            parse_reltype_feature("[coreferential]"),
+            vec!["coreferential".to_string()]
+        );
+    }
+
+    #[test]
+    fn parses_concatenated_reltypes() {
+        assert_eq!(
+            parse_reltype_feature("[coreferential,anaphoric]"),
+            vec!["coreferential".to_string(), "anaphoric".to_string()]
+        );
+    }
+
+    #[test]
+    fn round_trips_through_the_forward_format() {
+        let entries = vec![(0, vec![1, 2]), (3, vec![5])];
+        let formatted = format!(
+            "[{}]",
+            entries
+                .iter()
+                .map(|(sent, ids)| format!(
+                    "({},[{}])",
+                    sent,
+                    ids.iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        assert_eq!(parse_coref_feature(&formatted), entries);
+    }
+}